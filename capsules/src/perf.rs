@@ -9,28 +9,64 @@
 use crate::driver;
 pub const DRIVER_NUM: usize = driver::NUM::Perf as usize;
 
+use kernel::hil::perf::PerfCounter;
 use kernel::{AppId, Driver, ReturnCode};
-use riscv::csr;
 
-pub struct Perf;
+pub struct Perf<'a> {
+    perf: &'a dyn PerfCounter,
+}
 
-impl Driver for Perf {
+impl<'a> Perf<'a> {
+    pub fn new(perf: &'a dyn PerfCounter) -> Self {
+        Perf { perf }
+    }
+}
+
+impl<'a> Driver for Perf<'a> {
     /// Control the Perf system.
     ///
     /// ### `command_num`
     ///
     /// - `0`: Driver check.
-    /// - `1`: Get perf counter.
-    /// - `2`: Get number of instructions executed
-    fn command(&self, command_num: usize, _: usize, _: usize, _: AppId) -> ReturnCode {
+    /// - `1`: Get cycle count.
+    /// - `2`: Get number of instructions executed.
+    /// - `3`: Configure general event counter `data1` to count event `data2`.
+    /// - `4`: Start general event counter `data1`.
+    /// - `5`: Stop general event counter `data1`.
+    /// - `6`: Read general event counter `data1`.
+    fn command(&self, command_num: usize, data1: usize, data2: usize, _: AppId) -> ReturnCode {
         match command_num {
             0 /* check if present */ => ReturnCode::SuccessWithValue { value: 1 },
 
-            1 /* FIXME HACK This needs to be implemented in the HIL somehow */ =>
-                ReturnCode::SuccessWithValue { value: csr::CSR.mcycle.get() as usize },
-            
-            2 /* FIXME HACK This needs to be implemented in the HIL somehow */ =>
-                ReturnCode::SuccessWithValue { value: csr::CSR.minstret.get() as usize },
+            1 /* cycle count */ =>
+                ReturnCode::SuccessWithValue { value: self.perf.cycle_count() as usize },
+
+            2 /* instructions retired */ =>
+                ReturnCode::SuccessWithValue { value: self.perf.instruction_count() as usize },
+
+            3 /* configure event counter data1 to count event data2 */ =>
+                match self.perf.configure(data1, data2) {
+                    Ok(()) => ReturnCode::SUCCESS,
+                    Err(e) => e.into(),
+                },
+
+            4 /* start event counter data1 */ =>
+                match self.perf.start(data1) {
+                    Ok(()) => ReturnCode::SUCCESS,
+                    Err(e) => e.into(),
+                },
+
+            5 /* stop event counter data1 */ =>
+                match self.perf.stop(data1) {
+                    Ok(()) => ReturnCode::SUCCESS,
+                    Err(e) => e.into(),
+                },
+
+            6 /* read event counter data1 */ =>
+                match self.perf.read(data1) {
+                    Ok(count) => ReturnCode::SuccessWithValue { value: count as usize },
+                    Err(e) => e.into(),
+                },
 
             _ => ReturnCode::ENOSUPPORT,
         }
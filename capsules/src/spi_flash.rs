@@ -0,0 +1,363 @@
+//! A JEDEC SPI-NOR flash command layer, built on top of a `SpiMasterDevice`.
+//!
+//! This issues the standard JEDEC flash operations (Read Identification,
+//! Read Status Register-1, Write Enable, page program, sector/block erase)
+//! and reports completion through `SpiFlashClient`. It complements the
+//! memory-mapped (XIP) read path some SPI controllers expose directly in
+//! hardware, giving boards a way to use external QSPI flash as a
+//! config/key-value non-volatile storage region even when they have no
+//! other NVM.
+//!
+//! Program and erase operations must chain Write-Enable -> command ->
+//! status-poll before signalling completion; `wait_while_busy` drives that
+//! poll with a bounded retry count rather than hanging forever if a part
+//! never clears its write-in-progress bit.
+
+use core::cell::Cell;
+use kernel::hil::spi::{SpiMasterClient, SpiMasterDevice};
+use kernel::utilities::cells::{OptionalCell, TakeCell};
+use kernel::ErrorCode;
+
+/// JEDEC command opcodes used by `SpiFlash`.
+mod opcode {
+    pub const READ_ID: u8 = 0x9F;
+    pub const READ_STATUS1: u8 = 0x05;
+    pub const WRITE_ENABLE: u8 = 0x06;
+    pub const PAGE_PROGRAM: u8 = 0x02;
+    pub const SECTOR_ERASE: u8 = 0x20;
+    pub const BLOCK_ERASE: u8 = 0xD8;
+}
+
+/// Write-in-progress bit of Status Register-1.
+const SR1_WIP: u8 = 0x01;
+
+/// Number of address bytes JEDEC flash commands take.
+const ADDRESS_LEN: usize = 3;
+
+/// Length of an opcode + 3-byte address header.
+const COMMAND_HEADER_LEN: usize = 1 + ADDRESS_LEN;
+
+/// Largest payload a single `page_program` call may write, matching the
+/// standard JEDEC page size; page program must not cross a page boundary.
+const MAX_PAGE_PROGRAM_LEN: usize = 256;
+
+/// Size of the write-side scratch buffer: large enough to hold a page
+/// program's opcode + address header followed by a full page of data.
+const COMMAND_BUF_LEN: usize = COMMAND_HEADER_LEN + MAX_PAGE_PROGRAM_LEN;
+
+/// Size of the read-side scratch buffer: large enough for the longest
+/// response we read back (Read Identification's opcode + 3 id bytes).
+const READ_BUF_LEN: usize = COMMAND_HEADER_LEN;
+
+/// Maximum number of RDSR polls `wait_while_busy` will issue for a single
+/// program/erase operation before giving up and reporting `ErrorCode::FAIL`.
+const BUSY_POLL_LIMIT: usize = 10_000;
+
+/// The erase granularity requested from `SpiFlash::erase`.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum EraseSize {
+    /// A 4 KiB sector (opcode 0x20).
+    Sector,
+    /// A 64 KiB block (opcode 0xD8).
+    Block,
+}
+
+/// Asynchronous completion callbacks for `SpiFlash` operations.
+pub trait SpiFlashClient {
+    /// `read_identification` completed. `id` holds the 3 JEDEC ID bytes
+    /// (manufacturer, memory type, capacity) on success.
+    fn identification_done(&self, result: Result<(), ErrorCode>, id: [u8; 3]);
+
+    /// `page_program` completed; `data` is handed back to the caller.
+    fn write_done(&self, result: Result<(), ErrorCode>, data: &'static mut [u8]);
+
+    /// `erase` completed.
+    fn erase_done(&self, result: Result<(), ErrorCode>);
+}
+
+/// Which operation a Write-Enable/command/poll sequence is driving, so the
+/// shared state machine below knows how to report completion.
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum PendingOp {
+    Write,
+    Erase,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum State {
+    Idle,
+    /// Sent Read Identification, waiting for the id bytes.
+    ReadId,
+    /// Sent Write Enable, about to issue the program/erase command itself.
+    WriteEnable(PendingOp),
+    /// Sent the program/erase command itself, about to start polling RDSR.
+    Command(PendingOp),
+    /// Sent RDSR, waiting on the status byte to decide whether to poll again.
+    Polling(PendingOp),
+}
+
+pub struct SpiFlash<'a, S: SpiMasterDevice> {
+    spi: &'a S,
+    client: OptionalCell<&'a dyn SpiFlashClient>,
+    state: Cell<State>,
+    /// Write-side scratch buffer for command/address headers, status polls,
+    /// and (for page_program) the header plus the page data itself.
+    command_buf: TakeCell<'static, [u8]>,
+    /// Read-side scratch buffer for responses (Read ID bytes, RDSR status).
+    read_buf: TakeCell<'static, [u8]>,
+    /// Holds the caller's data buffer for the duration of a page_program,
+    /// returned unchanged via `SpiFlashClient::write_done`.
+    write_buf: TakeCell<'static, [u8]>,
+    /// The opcode/address to issue once the in-flight Write Enable completes.
+    next_command: Cell<(u8, u32)>,
+    poll_count: Cell<usize>,
+}
+
+impl<'a, S: SpiMasterDevice> SpiFlash<'a, S> {
+    pub fn new(
+        spi: &'a S,
+        command_buf: &'static mut [u8; COMMAND_BUF_LEN],
+        read_buf: &'static mut [u8; READ_BUF_LEN],
+    ) -> Self {
+        SpiFlash {
+            spi,
+            client: OptionalCell::empty(),
+            state: Cell::new(State::Idle),
+            command_buf: TakeCell::new(command_buf),
+            read_buf: TakeCell::new(read_buf),
+            write_buf: TakeCell::empty(),
+            next_command: Cell::new((0, 0)),
+            poll_count: Cell::new(0),
+        }
+    }
+
+    pub fn set_client(&self, client: &'a dyn SpiFlashClient) {
+        self.client.set(client);
+    }
+
+    /// Issue a Read Identification (0x9F) command.
+    pub fn read_identification(&self) -> Result<(), ErrorCode> {
+        if self.state.get() != State::Idle {
+            return Err(ErrorCode::BUSY);
+        }
+        let cmd_buf = match self.command_buf.take() {
+            Some(buf) => buf,
+            None => return Err(ErrorCode::NOMEM),
+        };
+        let read_buf = match self.read_buf.take() {
+            Some(buf) => buf,
+            None => {
+                self.command_buf.replace(cmd_buf);
+                return Err(ErrorCode::NOMEM);
+            }
+        };
+        cmd_buf[0] = opcode::READ_ID;
+        cmd_buf[1] = 0;
+        cmd_buf[2] = 0;
+        cmd_buf[3] = 0;
+        self.state.set(State::ReadId);
+        self.start_transfer(cmd_buf, Some(read_buf), READ_BUF_LEN)
+    }
+
+    /// Program `data` starting at `address`. `data.len()` must not exceed
+    /// `MAX_PAGE_PROGRAM_LEN` or cross a page boundary, per JEDEC page
+    /// program semantics.
+    pub fn page_program(
+        &self,
+        address: u32,
+        data: &'static mut [u8],
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if self.state.get() != State::Idle {
+            return Err((ErrorCode::BUSY, data));
+        }
+        if data.len() > MAX_PAGE_PROGRAM_LEN {
+            return Err((ErrorCode::SIZE, data));
+        }
+        self.write_buf.replace(data);
+        self.poll_count.set(0);
+        match self.send_write_enable(PendingOp::Write, opcode::PAGE_PROGRAM, address) {
+            Ok(()) => Ok(()),
+            Err(e) => Err((e, self.write_buf.take().unwrap())),
+        }
+    }
+
+    /// Erase the `size`-granularity region starting at `address`.
+    pub fn erase(&self, address: u32, size: EraseSize) -> Result<(), ErrorCode> {
+        if self.state.get() != State::Idle {
+            return Err(ErrorCode::BUSY);
+        }
+        self.poll_count.set(0);
+        let opcode = match size {
+            EraseSize::Sector => opcode::SECTOR_ERASE,
+            EraseSize::Block => opcode::BLOCK_ERASE,
+        };
+        self.send_write_enable(PendingOp::Erase, opcode, address)
+    }
+
+    /// Issue Write Enable; once it completes, `read_write_done` issues
+    /// `(opcode, address)` itself before moving on to the status poll.
+    fn send_write_enable(&self, op: PendingOp, opcode: u8, address: u32) -> Result<(), ErrorCode> {
+        self.next_command.set((opcode, address));
+        self.command_buf
+            .take()
+            .map_or(Err(ErrorCode::NOMEM), |buf| {
+                buf[0] = opcode::WRITE_ENABLE;
+                self.state.set(State::WriteEnable(op));
+                self.start_transfer(buf, None, 1)
+            })
+    }
+
+    /// Issue the page-program/erase command itself: opcode, a 3-byte
+    /// big-endian address, and (for a page program) the page data itself,
+    /// all as a single transfer so CS stays asserted across the whole frame.
+    fn issue_command(&self, op: PendingOp, opcode: u8, address: u32) -> Result<(), ErrorCode> {
+        self.command_buf
+            .take()
+            .map_or(Err(ErrorCode::NOMEM), |buf| {
+                buf[0] = opcode;
+                buf[1] = (address >> 16) as u8;
+                buf[2] = (address >> 8) as u8;
+                buf[3] = address as u8;
+                let len = match op {
+                    PendingOp::Write => {
+                        let data_len = self
+                            .write_buf
+                            .map(|data| {
+                                buf[COMMAND_HEADER_LEN..COMMAND_HEADER_LEN + data.len()]
+                                    .copy_from_slice(data);
+                                data.len()
+                            })
+                            .unwrap_or(0);
+                        COMMAND_HEADER_LEN + data_len
+                    }
+                    PendingOp::Erase => COMMAND_HEADER_LEN,
+                };
+                self.state.set(State::Command(op));
+                self.start_transfer(buf, None, len)
+            })
+    }
+
+    fn start_transfer(
+        &self,
+        write_buf: &'static mut [u8],
+        read_buf: Option<&'static mut [u8]>,
+        len: usize,
+    ) -> Result<(), ErrorCode> {
+        match self.spi.read_write_bytes(write_buf, read_buf, len) {
+            Ok(()) => Ok(()),
+            Err((e, write_buf, read_buf)) => {
+                self.command_buf.replace(write_buf);
+                if let Some(buf) = read_buf {
+                    self.read_buf.replace(buf);
+                }
+                self.state.set(State::Idle);
+                Err(e)
+            }
+        }
+    }
+
+    /// Poll Read Status Register-1 and look at the WIP bit once the response
+    /// comes back in `read_write_done`, bounded by `BUSY_POLL_LIMIT` so a
+    /// part that never clears WIP can't hang a program/erase forever.
+    fn wait_while_busy(&self, op: PendingOp) -> Result<(), ErrorCode> {
+        if self.poll_count.get() >= BUSY_POLL_LIMIT {
+            return Err(ErrorCode::FAIL);
+        }
+        self.poll_count.set(self.poll_count.get() + 1);
+        let cmd_buf = match self.command_buf.take() {
+            Some(buf) => buf,
+            None => return Err(ErrorCode::NOMEM),
+        };
+        let read_buf = match self.read_buf.take() {
+            Some(buf) => buf,
+            None => {
+                self.command_buf.replace(cmd_buf);
+                return Err(ErrorCode::NOMEM);
+            }
+        };
+        cmd_buf[0] = opcode::READ_STATUS1;
+        cmd_buf[1] = 0;
+        self.state.set(State::Polling(op));
+        self.start_transfer(cmd_buf, Some(read_buf), 2)
+    }
+
+    fn finish(&self, op: PendingOp, result: Result<(), ErrorCode>) {
+        self.state.set(State::Idle);
+        match op {
+            PendingOp::Write => {
+                let data = self.write_buf.take().unwrap();
+                self.client.map(|c| c.write_done(result, data));
+            }
+            PendingOp::Erase => {
+                self.client.map(|c| c.erase_done(result));
+            }
+        }
+    }
+}
+
+impl<'a, S: SpiMasterDevice> SpiMasterClient for SpiFlash<'a, S> {
+    fn read_write_done(
+        &self,
+        write_buffer: &'static mut [u8],
+        read_buffer: Option<&'static mut [u8]>,
+        _len: usize,
+        status: Result<(), ErrorCode>,
+    ) {
+        let state = self.state.get();
+        self.command_buf.replace(write_buffer);
+        if let Some(buf) = read_buffer {
+            self.read_buf.replace(buf);
+        }
+
+        if let Err(e) = status {
+            match state {
+                State::ReadId => {
+                    self.state.set(State::Idle);
+                    self.client.map(|c| c.identification_done(Err(e), [0; 3]));
+                }
+                State::WriteEnable(op) | State::Command(op) | State::Polling(op) => {
+                    self.finish(op, Err(e));
+                }
+                State::Idle => {}
+            }
+            return;
+        }
+
+        match state {
+            State::ReadId => {
+                let id = self
+                    .read_buf
+                    .map(|buf| [buf[1], buf[2], buf[3]])
+                    .unwrap_or([0; 3]);
+                self.state.set(State::Idle);
+                self.client.map(|c| c.identification_done(Ok(()), id));
+            }
+
+            State::WriteEnable(op) => {
+                let (opcode, address) = self.next_command.get();
+                if let Err(e) = self.issue_command(op, opcode, address) {
+                    self.finish(op, Err(e));
+                }
+            }
+
+            State::Command(op) => {
+                if let Err(e) = self.wait_while_busy(op) {
+                    self.finish(op, Err(e));
+                }
+            }
+
+            State::Polling(op) => {
+                let status_byte = self.read_buf.map(|buf| buf[1]).unwrap_or(0);
+                if status_byte & SR1_WIP != 0 {
+                    if let Err(e) = self.wait_while_busy(op) {
+                        self.finish(op, Err(e));
+                    }
+                } else {
+                    self.finish(op, Ok(()));
+                }
+            }
+
+            State::Idle => {}
+        }
+    }
+}
@@ -0,0 +1,154 @@
+//! Hardware performance-monitoring counters.
+//!
+//! Exposes the RISC-V fixed `mcycle`/`minstret` counters and the general
+//! `mhpmcounter3..31`/`mhpmevent3..31` event counters, gated by
+//! `mcountinhibit`, through `kernel::hil::perf::PerfCounter`.
+//!
+//! The general counters/selectors are addressed directly by CSR mnemonic
+//! through inline assembly rather than through named fields on an external
+//! `riscv` crate struct: there are 29 of each (`mhpmcounter3..31`,
+//! `mhpmcounter3..31h`, `mhpmevent3..31`), too many to vendor reliably by
+//! name, and this keeps the driver self-contained and independently
+//! compilable.
+
+use core::arch::asm;
+use kernel::hil::perf::PerfCounter;
+use kernel::ErrorCode;
+
+/// Index of the first configurable general event counter (`mhpmcounter3`).
+const FIRST_COUNTER: usize = 3;
+/// Index of the last configurable general event counter (`mhpmcounter31`).
+const LAST_COUNTER: usize = 31;
+
+pub struct Perf;
+
+impl Perf {
+    pub const fn new() -> Self {
+        Perf
+    }
+}
+
+impl Default for Perf {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Reads the 32-bit CSR named `$csr` (a string literal, or a `concat!` of
+/// literals naming one of the `mhpmcounter`/`mhpmevent` family).
+macro_rules! csrr {
+    ($csr:expr) => {{
+        let val: u32;
+        unsafe {
+            asm!(concat!("csrr {0}, ", $csr), out(reg) val);
+        }
+        val
+    }};
+}
+
+/// Writes `$val` to the 32-bit CSR named `$csr`.
+macro_rules! csrw {
+    ($csr:expr, $val:expr) => {{
+        unsafe {
+            asm!(concat!("csrw ", $csr, ", {0}"), in(reg) $val);
+        }
+    }};
+}
+
+/// Read a 64-bit counter exposed as a low/high CSR pair, re-reading the low
+/// half if the high half changed in between (a low-to-high rollover landed
+/// between the two reads).
+fn read64(high: impl Fn() -> u32, low: impl Fn() -> u32) -> u64 {
+    loop {
+        let hi1 = high();
+        let lo = low();
+        let hi2 = high();
+        if hi1 == hi2 {
+            return ((hi1 as u64) << 32) | (lo as u64);
+        }
+    }
+}
+
+/// Writes event id `$val` to the `mhpmevent$n` CSR selected by `$idx`.
+macro_rules! set_mhpmevent {
+    ($idx:expr, $val:expr, $($n:literal),+ $(,)?) => {
+        match $idx {
+            $($n => { csrw!(concat!("mhpmevent", $n), $val); Ok(()) })+
+            _ => Err(ErrorCode::INVAL),
+        }
+    };
+}
+
+/// Reads the 64-bit `mhpmcounter$n`/`mhpmcounter$nh` CSR pair selected by
+/// `$idx`.
+macro_rules! read_mhpmcounter {
+    ($idx:expr, $($n:literal),+ $(,)?) => {
+        match $idx {
+            $($n => Ok(read64(
+                || csrr!(concat!("mhpmcounter", $n, "h")),
+                || csrr!(concat!("mhpmcounter", $n)),
+            )),)+
+            _ => Err(ErrorCode::INVAL),
+        }
+    };
+}
+
+impl PerfCounter for Perf {
+    fn num_counters(&self) -> usize {
+        LAST_COUNTER - FIRST_COUNTER + 1
+    }
+
+    fn cycle_count(&self) -> u64 {
+        read64(|| csrr!("mcycleh"), || csrr!("mcycle"))
+    }
+
+    fn instruction_count(&self) -> u64 {
+        read64(|| csrr!("minstreth"), || csrr!("minstret"))
+    }
+
+    fn configure(&self, counter_idx: usize, event_id: usize) -> Result<(), ErrorCode> {
+        if counter_idx >= self.num_counters() {
+            return Err(ErrorCode::INVAL);
+        }
+        self.stop(counter_idx)?;
+        let idx = counter_idx + FIRST_COUNTER;
+        let event_id = event_id as u32;
+        set_mhpmevent!(idx, event_id,
+            3, 4, 5, 6, 7, 8, 9, 10,
+            11, 12, 13, 14, 15, 16, 17, 18,
+            19, 20, 21, 22, 23, 24, 25, 26,
+            27, 28, 29, 30, 31,
+        )
+    }
+
+    fn start(&self, counter_idx: usize) -> Result<(), ErrorCode> {
+        if counter_idx >= self.num_counters() {
+            return Err(ErrorCode::INVAL);
+        }
+        let bit = counter_idx + FIRST_COUNTER;
+        csrw!("mcountinhibit", csrr!("mcountinhibit") & !(1 << bit));
+        Ok(())
+    }
+
+    fn stop(&self, counter_idx: usize) -> Result<(), ErrorCode> {
+        if counter_idx >= self.num_counters() {
+            return Err(ErrorCode::INVAL);
+        }
+        let bit = counter_idx + FIRST_COUNTER;
+        csrw!("mcountinhibit", csrr!("mcountinhibit") | (1 << bit));
+        Ok(())
+    }
+
+    fn read(&self, counter_idx: usize) -> Result<u64, ErrorCode> {
+        if counter_idx >= self.num_counters() {
+            return Err(ErrorCode::INVAL);
+        }
+        let idx = counter_idx + FIRST_COUNTER;
+        read_mhpmcounter!(idx,
+            3, 4, 5, 6, 7, 8, 9, 10,
+            11, 12, 13, 14, 15, 16, 17, 18,
+            19, 20, 21, 22, 23, 24, 25, 26,
+            27, 28, 29, 30, 31,
+        )
+    }
+}
@@ -1,10 +1,87 @@
 //! SPI instantiation.
 
+use core::cell::Cell;
+
+use crate::prci;
 use kernel::utilities::StaticRef;
-use sifive::spi::SpiRegisters;
+use sifive::spi::{ClockClient, ClockSource, SpiRegisters};
+
+/// QSPI0, SPI1, and SPI2 all run off the same PRCI peripheral clock, so all
+/// three may register as listeners.
+const MAX_CLOCK_CLIENTS: usize = 3;
+
+/// `prci::set_clock_client` only tracks a single listener; this fans its one
+/// notification back out to every `Spi` instance that subscribed, so QSPI0,
+/// SPI1, and SPI2 can all register independently without the later ones
+/// silently displacing the earlier ones.
+struct ClockClientList {
+    clients: [Cell<Option<&'static dyn ClockClient>>; MAX_CLOCK_CLIENTS],
+}
+
+impl ClockClientList {
+    const fn new() -> Self {
+        ClockClientList {
+            clients: [Cell::new(None), Cell::new(None), Cell::new(None)],
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.clients.iter().all(|slot| slot.get().is_none())
+    }
+
+    fn register(&self, client: &'static dyn ClockClient) {
+        for slot in self.clients.iter() {
+            if slot.get().is_none() {
+                slot.set(Some(client));
+                return;
+            }
+        }
+    }
+}
+
+impl ClockClient for ClockClientList {
+    fn clock_frequency_changed(&self, new_frequency_hz: u32) {
+        for slot in self.clients.iter() {
+            if let Some(client) = slot.get() {
+                client.clock_frequency_changed(new_frequency_hz);
+            }
+        }
+    }
+}
+
+static CLOCK_CLIENTS: ClockClientList = ClockClientList::new();
+
+/// Adapts the E310x PRCI peripheral clock to `sifive::spi::ClockSource`, so
+/// `Spi::set_rate`/`get_rate` track the real peripheral input frequency
+/// instead of an assumed constant.
+pub struct PeripheralClock;
+
+impl ClockSource for PeripheralClock {
+    fn frequency_hz(&self) -> u32 {
+        prci::peripheral_clock_frequency()
+    }
+
+    fn set_clock_client(&self, client: &'static dyn ClockClient) {
+        // Register ourselves with `prci` exactly once, on the first caller;
+        // every subsequent caller just joins the fan-out list above.
+        let first = CLOCK_CLIENTS.is_empty();
+        CLOCK_CLIENTS.register(client);
+        if first {
+            prci::set_clock_client(&CLOCK_CLIENTS);
+        }
+    }
+}
+
+pub static QSPI0_CLOCK: PeripheralClock = PeripheralClock;
+pub static SPI1_CLOCK: PeripheralClock = PeripheralClock;
+pub static SPI2_CLOCK: PeripheralClock = PeripheralClock;
 
 pub const QSPI0_BASE: StaticRef<SpiRegisters> =
     unsafe { StaticRef::new(0x10014000 as *const SpiRegisters) };
+/// Memory-mapped (XIP) window for QSPI0. Once flash mode is enabled with
+/// `Spi::enable_flash_mode`, reads from this region are served directly by
+/// the controller's flash interface rather than by `read_write_bytes`.
+pub const QSPI0_MMAP_BASE: usize = 0x2000_0000;
 pub const SPI1_BASE: StaticRef<SpiRegisters> =
     unsafe { StaticRef::new(0x10024000 as *const SpiRegisters) };
 pub const SPI2_BASE: StaticRef<SpiRegisters> =
@@ -134,6 +134,74 @@ register_bitfields![u8,
     ],
 ];
 
+/// Number of data lanes used for a SPI phase (command/address/data), shared
+/// between the flash (XIP) instruction format and regular transfers.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum SpiProtocol {
+    Single,
+    Dual,
+    Quad,
+}
+
+impl From<SpiProtocol> for u32 {
+    fn from(proto: SpiProtocol) -> u32 {
+        match proto {
+            SpiProtocol::Single => 0,
+            SpiProtocol::Dual => 1,
+            SpiProtocol::Quad => 2,
+        }
+    }
+}
+
+/// Supplies the frequency of the clock driving a SPI controller (its
+/// `f_in`), letting `Spi::set_rate`/`get_rate` compute `sckdiv` against the
+/// real peripheral input frequency instead of an assumed constant.
+pub trait ClockSource {
+    /// Current peripheral input clock frequency, in Hz.
+    fn frequency_hz(&self) -> u32;
+
+    /// Register `client` to be notified via
+    /// `ClockClient::clock_frequency_changed` whenever this frequency
+    /// changes (e.g. after a PRCI reconfiguration).
+    fn set_clock_client(&self, client: &'static dyn ClockClient);
+}
+
+/// Notified by a `ClockSource` when its frequency changes.
+pub trait ClockClient {
+    fn clock_frequency_changed(&self, new_frequency_hz: u32);
+}
+
+/// Compute the `sckdiv` divisor for `rate`, given the peripheral input clock
+/// `f_in`, along with the rate that divisor actually achieves. Returns
+/// `None` if `rate` can't be reached at this `f_in` (`div` would be negative
+/// or wouldn't fit the 12-bit `sckdiv` field).
+fn compute_sckdiv(f_in: u32, rate: u32) -> Option<(u32, u32)> {
+    if f_in == 0 || rate == 0 {
+        return None;
+    }
+    // div = f_in / (2 * f_sck) - 1
+    let undivided = f_in / (2 * rate);
+    if undivided == 0 {
+        return None;
+    }
+    let div = undivided - 1;
+    if div > 0xFFF {
+        return None;
+    }
+    let real_rate = f_in / (2 * (div + 1));
+    Some((div, real_rate))
+}
+
+/// A chip select line: either one of the controller's native hardware CS
+/// lines (selected via `csid`/`csmode`), or an arbitrary GPIO pin driven
+/// manually by the driver. This lets a board mix native and software chip
+/// selects when it has more SPI peripherals than native CS lines.
+#[derive(Copy, Clone)]
+pub enum ChipSelect {
+    Hardware(u8),
+    Gpio(&'static gpio::GpioPin),
+}
+
 pub struct Spi {
     registers: StaticRef<SpiRegisters>,
     client: OptionalCell<&'static dyn spi::SpiMasterClient>,
@@ -143,10 +211,29 @@ pub struct Spi {
     io_len: Cell<usize>,
     tx_offset: Cell<usize>,
     rx_offset: Cell<usize>,
+    /// Lane width used for the next `read_write_bytes` transfer. `Dual`/`Quad`
+    /// are half-duplex, so transfers in those modes move through a Tx phase
+    /// followed by a Rx phase rather than shifting in and out at once.
+    protocol: Cell<SpiProtocol>,
+    /// Number of bytes to capture into `rx_buf` once the Tx phase of a
+    /// half-duplex transfer drains, or 0 if the transfer is write-only.
+    half_duplex_rx_len: Cell<usize>,
+    /// Byte count reported to the client's `read_write_done` callback for a
+    /// half-duplex transfer (the Rx phase length if one was requested,
+    /// otherwise the Tx phase length).
+    report_len: Cell<usize>,
+    /// The currently selected chip select line, set by `specify_chip_select`.
+    chip_select: Cell<ChipSelect>,
+    /// Supplies this controller's peripheral input clock frequency.
+    clock: OptionalCell<&'static dyn ClockSource>,
+    /// SCK rate last requested through `set_rate`, re-applied against the
+    /// current `f_in` whenever `clock_frequency_changed` fires. 0 if
+    /// `set_rate` has never been called.
+    target_rate: Cell<u32>,
 }
 
 impl spi::SpiMaster for Spi {
-    type ChipSelect = u8;
+    type ChipSelect = ChipSelect;
 
     fn init(&self) -> Result<(), ErrorCode> {
         // Set up SPI interface
@@ -170,10 +257,16 @@ impl spi::SpiMaster for Spi {
         // change to AUTO briefly to release CS.
         self.registers.csmode.modify(csmode::mode::HOLD);
 
+        // Make sure flash (XIP) mode is off: while it's enabled, programmed-I/O
+        // access through txdata/rxdata is unavailable.
+        self.registers.fctrl.modify(fctrl::en::CLEAR);
+
         // Set up internal state
         self.io_len.set(0);
         self.tx_offset.set(0);
         self.rx_offset.set(0);
+        self.half_duplex_rx_len.set(0);
+        self.protocol.set(SpiProtocol::Single);
         self.busy.set(false);
         Ok(())
     }
@@ -202,13 +295,67 @@ impl spi::SpiMaster for Spi {
         if self.busy.get() == true {
             return Err((ErrorCode::BUSY, write_buffer, read_buffer));
         }
+        // txdata/rxdata are unavailable while the controller is busy serving
+        // memory-mapped flash reads.
+        if self.registers.fctrl.read(fctrl::en) == 1 {
+            return Err((ErrorCode::BUSY, write_buffer, read_buffer));
+        }
+
+        let protocol = self.protocol.get();
+        // Dual/Quad are half-duplex: the controller cannot shift data in and
+        // out at the same time, so a literal byte-for-byte full-duplex
+        // exchange (equal-length write/read buffers, as the Single-protocol
+        // path below performs) isn't representable in hardware. This is
+        // rejected before the controller is marked busy or CS is asserted.
+        if protocol != SpiProtocol::Single
+            && tx_len > 0
+            && read_buffer.as_ref().map_or(0, |rx_buf| rx_buf.len()) == tx_len
+        {
+            return Err((ErrorCode::INVAL, write_buffer, read_buffer));
+        }
 
         self.busy.set(true);
+        self.assert_cs();
 
         let rx_len = match &read_buffer {
             None => 0,
             Some(rx_buf) => rx_buf.len(),
         };
+
+        if protocol != SpiProtocol::Single {
+            self.registers.fmt.write(
+                fmt::proto.val(protocol.into()) + fmt::endian::Big + fmt::dir::Tx + fmt::len.val(8),
+            );
+
+            let first_chunk = cmp::min(7, tx_len);
+            for val in &write_buffer[0..first_chunk] {
+                let val: u32 = (*val).into();
+                self.registers.txdata.modify(txdata::data.val(val));
+            }
+
+            self.io_len.set(tx_len);
+            self.tx_offset.set(first_chunk);
+            self.tx_buf.replace(write_buffer);
+            self.half_duplex_rx_len.set(rx_len);
+            self.rx_offset.set(0);
+            self.report_len.set(if rx_len > 0 { rx_len } else { tx_len });
+            read_buffer.map(|rx_buf| self.rx_buf.replace(rx_buf));
+
+            // Fire txwm once the FIFO fully drains; handle_interrupt refills
+            // it until write_buffer is exhausted, then either starts the Rx
+            // phase or signals completion.
+            self.registers.txmark.write(txmark::txmark.val(1));
+            self.registers.ie.modify(ie::txwm::SET + ie::rxwm::CLEAR);
+            return Ok(());
+        }
+
+        // A prior transfer may have left fmt in a Dual/Quad, Tx-only
+        // configuration; restore the full-duplex Single/Rx setup this path
+        // expects.
+        self.registers
+            .fmt
+            .write(fmt::proto::Single + fmt::endian::Big + fmt::dir::Rx + fmt::len.val(8));
+
         let io_len = cmp::min(tx_len, rx_len);
 
         // TX FIFO has a max depth of 7 bytes, per the 3 bits in the watermark register, write up
@@ -256,6 +403,9 @@ impl spi::SpiMaster for Spi {
         if self.busy.get() {
             return Err(ErrorCode::BUSY);
         }
+        if self.registers.fctrl.read(fctrl::en) == 1 {
+            return Err(ErrorCode::BUSY);
+        }
         self.registers.txdata.write(txdata::data.val(val.into()));
         while self.registers.rxdata_empty.read(rxdata_empty::empty) == 1 {
             // Do nothing, just wait until we get data
@@ -265,35 +415,53 @@ impl spi::SpiMaster for Spi {
     }
 
     fn specify_chip_select(&self, cs: Self::ChipSelect) -> Result<(), ErrorCode> {
-        self.registers.csid.set(cs.into());
+        // Release whichever chip select is currently asserted before
+        // selecting a new one, so two devices are never active at once
+        // (a native-CS switch is safe as-is: csid's single multiplexer only
+        // ever drives one line low at a time).
+        if let ChipSelect::Gpio(prev_pin) = self.chip_select.get() {
+            prev_pin.set();
+        }
+        match cs {
+            ChipSelect::Hardware(csid) => {
+                self.registers.csid.set(csid.into());
+                self.registers.csmode.modify(csmode::mode::HOLD);
+            }
+            ChipSelect::Gpio(pin) => {
+                // Make sure the hardware never toggles a native CS line while
+                // a GPIO pin is acting as chip select, and assert the pin
+                // immediately so byte-level callers (read_write_byte et al.,
+                // which don't go through assert_cs/release_cs) see the same
+                // "selected means active" behavior as ChipSelect::Hardware's
+                // csmode::HOLD above.
+                self.registers.csmode.modify(csmode::mode::OFF);
+                pin.clear();
+            }
+        }
+        self.chip_select.set(cs);
         Ok(())
     }
 
     fn set_rate(&self, rate: u32) -> Result<u32, ErrorCode> {
-        // (f_in / f_sck )/ 2 - 1 = div
-        // FIXME right now, f_in is hardcoded to be 16MHz
-        // Min rate is is 8000000/4096 = 1954
-        // max is 8000000
-        if rate < 1954 || rate > 8_000_000 {
-            return Err(ErrorCode::INVAL);
-        }
-
         if self.busy.get() {
             return Err(ErrorCode::BUSY);
         }
 
-        let real_rate = rate;
+        let f_in = self.clock.map_or(0, |clock| clock.frequency_hz());
+        let (div, real_rate) = compute_sckdiv(f_in, rate).ok_or(ErrorCode::INVAL)?;
 
-        let div = 8_000_000 / real_rate - 1;
         self.registers.sckdiv.write(sckdiv::div.val(div));
+        self.target_rate.set(rate);
         Ok(real_rate)
     }
 
     fn get_rate(&self) -> u32 {
-        // FIXME right now, f_in is hardcoded to be 16MHz
-        // f_sck = f_in / (2 (div + 1))
+        let f_in = self.clock.map_or(0, |clock| clock.frequency_hz());
         let div = self.registers.sckdiv.read(sckdiv::div);
-        8000000 / (div + 1)
+        if f_in == 0 {
+            return 0;
+        }
+        f_in / (2 * (div + 1))
     }
 
     fn set_polarity(&self, polarity: spi::ClockPolarity) -> Result<(), ErrorCode> {
@@ -339,16 +507,38 @@ impl spi::SpiMaster for Spi {
     }
 
     fn hold_low(&self) {
-        self.registers.csmode.modify(csmode::mode::HOLD);
+        match self.chip_select.get() {
+            ChipSelect::Hardware(_) => self.registers.csmode.modify(csmode::mode::HOLD),
+            ChipSelect::Gpio(pin) => pin.clear(),
+        }
     }
 
     fn release_low(&self) {
-        self.registers.csmode.modify(csmode::mode::AUTO);
+        match self.chip_select.get() {
+            ChipSelect::Hardware(_) => self.registers.csmode.modify(csmode::mode::AUTO),
+            ChipSelect::Gpio(pin) => pin.set(),
+        }
+    }
+}
+
+impl ClockClient for Spi {
+    fn clock_frequency_changed(&self, new_frequency_hz: u32) {
+        let target = self.target_rate.get();
+        if target == 0 {
+            // set_rate has never been called; nothing to preserve.
+            return;
+        }
+        if let Some((div, _)) = compute_sckdiv(new_frequency_hz, target) {
+            self.registers.sckdiv.write(sckdiv::div.val(div));
+        }
+        // Otherwise the requested rate is no longer reachable at the new
+        // f_in; leave sckdiv as-is rather than silently picking a different
+        // rate the caller didn't ask for.
     }
 }
 
 impl Spi {
-    pub fn new(base: StaticRef<SpiRegisters>) -> Self {
+    pub fn new(base: StaticRef<SpiRegisters>, clock: &'static dyn ClockSource) -> Self {
         Spi {
             registers: base,
             client: OptionalCell::empty(),
@@ -358,10 +548,64 @@ impl Spi {
             rx_offset: Cell::new(0),
             tx_buf: TakeCell::empty(),
             rx_buf: TakeCell::empty(),
+            protocol: Cell::new(SpiProtocol::Single),
+            half_duplex_rx_len: Cell::new(0),
+            report_len: Cell::new(0),
+            chip_select: Cell::new(ChipSelect::Hardware(0)),
+            clock: OptionalCell::new(clock),
+            target_rate: Cell::new(0),
+        }
+    }
+
+    /// Register this `Spi` to be notified when `clock`'s frequency changes,
+    /// so a PRCI reconfiguration re-derives `sckdiv` to preserve the last
+    /// rate requested through `set_rate`. Must be called after `self` has
+    /// been placed in its final, `'static` location (e.g. via
+    /// `kernel::static_init!`).
+    pub fn set_clock_client(&'static self) {
+        self.clock.map(|clock| clock.set_clock_client(self));
+    }
+
+    /// Drive the selected chip select active (low) at the start of a
+    /// transfer. For a native CS line this is a no-op: `csmode::HOLD` already
+    /// keeps it held low across the whole transfer.
+    fn assert_cs(&self) {
+        if let ChipSelect::Gpio(pin) = self.chip_select.get() {
+            pin.clear();
+        }
+    }
+
+    /// Release the selected chip select (drive it back high) once a transfer
+    /// completes. For a native CS line, toggling `csmode` to `AUTO` and back
+    /// to `HOLD` causes the hardware to release it.
+    fn release_cs(&self) {
+        match self.chip_select.get() {
+            ChipSelect::Hardware(_) => {
+                self.registers.csmode.modify(csmode::mode::AUTO);
+                self.registers.csmode.modify(csmode::mode::HOLD);
+            }
+            ChipSelect::Gpio(pin) => pin.set(),
         }
     }
 
+    /// Select the lane width used by the next `read_write_bytes` transfer.
+    /// `Dual`/`Quad` are half-duplex: the transfer moves through a Tx phase
+    /// (sending `write_buffer`) followed by a Rx phase (filling
+    /// `read_buffer`, if any) instead of shifting in and out at once.
+    pub fn set_protocol(&self, protocol: SpiProtocol) -> Result<(), ErrorCode> {
+        if self.busy.get() {
+            return Err(ErrorCode::BUSY);
+        }
+        self.protocol.set(protocol);
+        Ok(())
+    }
+
     pub fn handle_interrupt(&self) {
+        if self.protocol.get() != SpiProtocol::Single {
+            self.handle_interrupt_half_duplex();
+            return;
+        }
+
         let ip_reg = &self.registers.ip;
 
         let rx_watermark = ip_reg.read(ip::rxwm) == 1;
@@ -437,10 +681,7 @@ impl Spi {
         if (self.tx_offset.get() == self.io_len.get())
             && (self.rx_offset.get() == self.io_len.get())
         {
-            // Toggle to AUTO to cause hardware to release CS now that transfer is done
-            self.registers.csmode.modify(csmode::mode::AUTO);
-            // ... and switch back to HOLD mode for the next transfer
-            self.registers.csmode.modify(csmode::mode::HOLD);
+            self.release_cs();
 
             // Disable interrupts
             self.registers.ie.modify(ie::txwm::CLEAR + ie::rxwm::CLEAR);
@@ -463,14 +704,177 @@ impl Spi {
         }
     }
 
+    /// `handle_interrupt` for Dual/Quad transfers, which move through a Tx
+    /// phase followed by an optional Rx phase rather than shifting in and out
+    /// at once. `fmt::dir` tells us which phase is currently active.
+    fn handle_interrupt_half_duplex(&self) {
+        let ip_reg = &self.registers.ip;
+        let in_tx_phase = self.registers.fmt.read(fmt::dir) == 1;
+
+        if in_tx_phase {
+            if ip_reg.read(ip::txwm) != 1 {
+                return;
+            }
+
+            let tx_offset = self.tx_offset.get();
+            let io_len = self.io_len.get();
+            if tx_offset < io_len {
+                // Keep feeding the Tx FIFO until write_buffer is exhausted.
+                let end_offset = cmp::min(tx_offset + 7, io_len);
+                self.tx_buf.take().map(|tx_buf| {
+                    for val in &tx_buf[tx_offset..end_offset] {
+                        self.registers.txdata.modify(txdata::data.val((*val).into()));
+                    }
+                    self.tx_offset.set(end_offset);
+                    self.tx_buf.replace(tx_buf);
+                });
+                return;
+            }
+
+            // write_buffer has fully drained out of the Tx FIFO. Either
+            // switch to the Rx phase to collect read_buffer, or finish now if
+            // none was requested.
+            let rx_len = self.half_duplex_rx_len.get();
+            if rx_len == 0 {
+                self.finish_half_duplex();
+                return;
+            }
+
+            self.registers.fmt.modify(fmt::dir::Rx);
+            // The controller still needs Tx FIFO writes to pump clock cycles
+            // while shifting data in on MISO; their value is ignored.
+            let first_chunk = cmp::min(7, rx_len);
+            for _ in 0..first_chunk {
+                self.registers.txdata.modify(txdata::data.val(0));
+            }
+            self.tx_offset.set(first_chunk);
+            self.rx_offset.set(0);
+            self.io_len.set(rx_len);
+            self.registers
+                .rxmark
+                .write(rxmark::rxmark.val((first_chunk - 1) as u32));
+            self.registers.ie.modify(ie::txwm::CLEAR + ie::rxwm::SET);
+            return;
+        }
+
+        // Rx phase: collect data shifted in on MISO.
+        if ip_reg.read(ip::rxwm) != 1 {
+            return;
+        }
+
+        let rxdata = &self.registers.rxdata_data;
+        let rxempty = &self.registers.rxdata_empty;
+        let is_rx_done = || self.rx_offset.get() == self.tx_offset.get();
+
+        self.rx_buf.take().map(|rx_buf| {
+            while !is_rx_done() {
+                assert!(rxempty.read(rxdata_empty::empty) == 0);
+                let val = rxdata.get();
+                rx_buf[self.rx_offset.get()] = val;
+                self.rx_offset.set(self.rx_offset.get() + 1);
+            }
+            self.rx_buf.replace(rx_buf);
+        });
+
+        if self.rx_offset.get() != self.io_len.get() {
+            // Pump the next chunk of dummy Tx bytes to keep clocking Rx data in.
+            let tx_offset = self.tx_offset.get();
+            let end_offset = cmp::min(tx_offset + 7, self.io_len.get());
+            for _ in tx_offset..end_offset {
+                self.registers.txdata.modify(txdata::data.val(0));
+            }
+            self.tx_offset.set(end_offset);
+            self.registers.rxmark.write(
+                rxmark::rxmark.val((end_offset - self.rx_offset.get() - 1) as u32),
+            );
+            return;
+        }
+
+        self.finish_half_duplex();
+    }
+
+    fn finish_half_duplex(&self) {
+        self.release_cs();
+        self.registers.ie.modify(ie::txwm::CLEAR + ie::rxwm::CLEAR);
+
+        self.client.map(|client| {
+            client.read_write_done(
+                self.tx_buf.take().unwrap(),
+                self.rx_buf.take(),
+                self.report_len.get(),
+                Ok(()),
+            );
+        });
+
+        self.io_len.set(0);
+        self.rx_offset.set(0);
+        self.tx_offset.set(0);
+        self.half_duplex_rx_len.set(0);
+        self.busy.set(false);
+    }
+
+    /// Enable memory-mapped (XIP) reads from an external flash attached to
+    /// this controller. Once enabled, CPU reads from the controller's mapped
+    /// memory window (e.g. 0x2000_0000 for QSPI0) are satisfied directly by
+    /// hardware, which repeatedly issues `cmd_code` followed by the read
+    /// address and `pad_cnt` dummy cycles.
+    ///
+    /// `cmd_code` is the flash read opcode (e.g. 0x03 for a single-lane read,
+    /// 0xEB for quad I/O), `addr_len` is the number of address bytes (usually
+    /// 3), and `pad_cnt` is the number of dummy/pad cycles the flash expects
+    /// between the address and the returned data. `cmd_proto`/`addr_proto`/
+    /// `data_proto` select the lane width used for each phase.
+    ///
+    /// While flash mode is enabled, `read_write_bytes`/`read_write_byte`
+    /// cannot use the controller and return `ErrorCode::BUSY`; call
+    /// `disable_flash_mode` first to resume programmed I/O.
+    pub fn enable_flash_mode(
+        &self,
+        cmd_code: u8,
+        addr_len: u32,
+        pad_cnt: u32,
+        cmd_proto: SpiProtocol,
+        addr_proto: SpiProtocol,
+        data_proto: SpiProtocol,
+    ) -> Result<(), ErrorCode> {
+        if self.busy.get() {
+            return Err(ErrorCode::BUSY);
+        }
+
+        self.registers.ffmt.write(
+            ffmt::cmd_en::SET
+                + ffmt::addr_len.val(addr_len)
+                + ffmt::pad_cnt.val(pad_cnt)
+                + ffmt::cmd_proto.val(cmd_proto.into())
+                + ffmt::addr_proto.val(addr_proto.into())
+                + ffmt::data_proto.val(data_proto.into())
+                + ffmt::cmd_code.val(cmd_code.into()),
+        );
+        self.registers.fctrl.modify(fctrl::en::SET);
+        Ok(())
+    }
+
+    /// Disable memory-mapped flash reads, returning the controller to normal
+    /// programmed I/O (`read_write_bytes`/`read_write_byte`).
+    pub fn disable_flash_mode(&self) {
+        self.registers.fctrl.modify(fctrl::en::CLEAR);
+    }
+
+    /// Mux `mosi`/`miso`/`sck` (and `cs`, if the chip select is one of the
+    /// controller's native hardware CS lines) to the SPI peripheral function.
+    /// Pass `cs: None` when chip select is instead a `ChipSelect::Gpio` pin:
+    /// such a pin is driven as a plain GPIO output and must not be muxed to
+    /// the native CS function.
     pub fn initialize_gpio_pins(
         &self,
-        cs: &gpio::GpioPin,
+        cs: Option<&gpio::GpioPin>,
         mosi: &gpio::GpioPin,
         miso: &gpio::GpioPin,
         sck: &gpio::GpioPin,
     ) {
-        cs.iof0();
+        if let Some(cs) = cs {
+            cs.iof0();
+        }
         mosi.iof0();
         miso.iof0();
         sck.iof0();
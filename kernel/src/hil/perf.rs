@@ -0,0 +1,32 @@
+//! Hardware performance-monitoring counters.
+
+use crate::ErrorCode;
+
+/// A hardware performance-monitoring unit: fixed cycle/instruction counters
+/// plus a bank of general-purpose event counters that can each be configured
+/// to count an implementation-defined event (e.g. cache misses, branch
+/// mispredictions).
+pub trait PerfCounter {
+    /// Number of general event counters available, indexed `0..num_counters()`.
+    fn num_counters(&self) -> usize;
+
+    /// Total CPU cycles elapsed since reset.
+    fn cycle_count(&self) -> u64;
+
+    /// Total instructions retired since reset.
+    fn instruction_count(&self) -> u64;
+
+    /// Configure general event counter `counter_idx` to count occurrences of
+    /// `event_id`. The counter is left stopped; call `start` to begin
+    /// counting. Returns `ErrorCode::INVAL` if `counter_idx` is out of range.
+    fn configure(&self, counter_idx: usize, event_id: usize) -> Result<(), ErrorCode>;
+
+    /// Start counting on general event counter `counter_idx`.
+    fn start(&self, counter_idx: usize) -> Result<(), ErrorCode>;
+
+    /// Stop general event counter `counter_idx`, preserving its count.
+    fn stop(&self, counter_idx: usize) -> Result<(), ErrorCode>;
+
+    /// Read the current count of general event counter `counter_idx`.
+    fn read(&self, counter_idx: usize) -> Result<u64, ErrorCode>;
+}